@@ -0,0 +1,197 @@
+//! On-disk state shared between cooperating processes.
+//!
+//! [`DataDir`] owns the directory holding the shared allocation file and the
+//! advisory lock guarding it. [`RootData`] is the serialized content of that
+//! file: the next port to scan from and the set of currently reserved ranges
+//! with their expiry timestamps.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::ops::Range;
+use std::path::PathBuf;
+
+use anyhow::Context as _;
+use fs2::FileExt as _;
+use serde::{Deserialize, Serialize};
+
+use crate::{UnixTimestamp, LOW};
+
+const DATA_FILE: &str = "data.json";
+const LOCK_FILE: &str = "lock";
+
+/// A single reserved range, stored keyed by its base port in [`RootData`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Reservation {
+    /// Exclusive end of the range (`base..end`).
+    end: u16,
+    /// Unix timestamp at which the reservation may be reclaimed.
+    expires: UnixTimestamp,
+}
+
+/// The serialized content of the shared allocation file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RootData {
+    /// Port to start the next scan from.
+    pub next: u16,
+    /// Random per-`DataDir` secret, generated once and persisted, used to key
+    /// the RFC 6056 per-process starting offset. Absent in files written
+    /// before this field existed; [`secret`](Self::secret) fills it in on
+    /// first use.
+    #[serde(default)]
+    secret: Option<[u8; 16]>,
+    /// Reserved ranges keyed by their base port.
+    ranges: BTreeMap<u16, Reservation>,
+}
+
+impl Default for RootData {
+    fn default() -> Self {
+        Self {
+            next: LOW,
+            secret: None,
+            ranges: BTreeMap::new(),
+        }
+    }
+}
+
+fn overlaps(a: &Range<u16>, b: &Range<u16>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+impl RootData {
+    /// Drop every reservation that has expired by `now`, returning the pruned
+    /// data so it can be used in place.
+    pub fn reclaim(mut self, now: UnixTimestamp) -> Self {
+        self.ranges.retain(|_, res| now < res.expires);
+        self
+    }
+
+    /// If `range` overlaps an existing reservation, return the first port past
+    /// that reservation (a good place to resume scanning); otherwise `None`.
+    pub fn contains(&self, range: Range<u16>) -> Option<u16> {
+        for (&base, res) in &self.ranges {
+            let existing = base..res.end;
+            if overlaps(&existing, &range) {
+                return Some(res.end);
+            }
+        }
+        None
+    }
+
+    /// Reserve `range` until `expires`, and record it as the starting point for
+    /// the next scan.
+    pub fn insert(&mut self, range: Range<u16>, expires: UnixTimestamp) {
+        self.next = range.end;
+        self.ranges.insert(range.start, Reservation {
+            end: range.end,
+            expires,
+        });
+    }
+
+    /// The per-`DataDir` secret, generating and storing it on first access so
+    /// every process sharing this file derives its offset from the same key.
+    /// The caller must persist the data afterwards for the secret to stick.
+    pub fn secret(&mut self) -> &[u8] {
+        &self.secret.get_or_insert_with(rand::random)[..]
+    }
+
+    /// Remove the reservation previously recorded for `range`, mirroring
+    /// [`insert`](Self::insert)'s keying by base port. Used by
+    /// [`port_free`](crate::port_free) to release a range before it expires.
+    pub fn remove(&mut self, range: Range<u16>) {
+        self.ranges.remove(&range.start);
+    }
+}
+
+/// The directory holding the shared allocation file, plus the advisory lock
+/// that serializes access to it across processes.
+#[derive(Debug)]
+pub struct DataDir {
+    root: PathBuf,
+    lock: Option<File>,
+}
+
+impl DataDir {
+    /// Open (creating if necessary) the data directory at `root`.
+    pub fn new(root: PathBuf) -> anyhow::Result<Self> {
+        fs::create_dir_all(&root)
+            .with_context(|| format!("Failed to create data dir: {}", root.display()))?;
+        Ok(Self { root, lock: None })
+    }
+
+    /// Run `f` while holding the exclusive advisory lock on the data directory,
+    /// releasing it afterwards whether `f` succeeds or fails.
+    pub fn with_lock<T>(
+        &mut self,
+        f: impl FnOnce(&mut DataDir) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let lock_path = self.root.join(LOCK_FILE);
+        let lock = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&lock_path)
+            .with_context(|| format!("Failed to open lock file: {}", lock_path.display()))?;
+        lock.lock_exclusive()
+            .with_context(|| format!("Failed to lock: {}", lock_path.display()))?;
+        self.lock = Some(lock);
+
+        let res = f(self);
+
+        if let Some(lock) = self.lock.take() {
+            let _ = fs2::FileExt::unlock(&lock);
+        }
+        res
+    }
+
+    /// Load the shared data, pruning entries already expired by `now`. A
+    /// missing or empty file is treated as the [`RootData::default`].
+    pub fn load_data(&self, now: UnixTimestamp) -> anyhow::Result<RootData> {
+        let path = self.root.join(DATA_FILE);
+        let data = match fs::read_to_string(&path) {
+            Ok(s) if !s.trim().is_empty() => serde_json::from_str(&s)
+                .with_context(|| format!("Failed to parse {}", path.display()))?,
+            Ok(_) => RootData::default(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => RootData::default(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to read {}", path.display()))
+            }
+        };
+        Ok(data.reclaim(now))
+    }
+
+    /// Persist the shared data back to the file.
+    pub fn store_data(&self, data: &RootData) -> anyhow::Result<()> {
+        let path = self.root.join(DATA_FILE);
+        let encoded = serde_json::to_string(data).context("Failed to serialize port alloc data")?;
+        fs::write(&path, encoded)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_remove_contains_round_trips() {
+        let mut data = RootData::default();
+        data.insert(10100..10110, 1);
+        assert!(data.contains(10100..10110).is_some());
+        assert!(data.contains(10105..10106).is_some());
+
+        data.remove(10100..10110);
+        assert_eq!(data.contains(10100..10110), None);
+    }
+
+    #[test]
+    fn secret_survives_serde_round_trip() {
+        let mut data = RootData::default();
+        let secret = data.secret().to_vec();
+
+        let encoded = serde_json::to_string(&data).unwrap();
+        let mut decoded: RootData = serde_json::from_str(&encoded).unwrap();
+
+        assert_eq!(decoded.secret(), secret.as_slice());
+    }
+}
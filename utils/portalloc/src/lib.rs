@@ -19,7 +19,7 @@ pub mod data;
 pub mod envs;
 pub mod util;
 
-use std::net::TcpListener;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpListener, UdpSocket};
 use std::path::PathBuf;
 
 // ports below 10k are typically used by normal software increasing change they
@@ -30,6 +30,7 @@ pub const LOW: u16 = 10000;
 pub const HIGH: u16 = 32000;
 
 use anyhow::bail;
+use thiserror::Error;
 use tracing::{debug, trace, warn};
 
 use crate::data::DataDir;
@@ -37,12 +38,68 @@ use crate::envs::FM_PORTALLOC_DATA_DIR_ENV;
 
 const LOG_PORT_ALLOC: &str = "port-alloc";
 
+/// Errors returned by [`port_alloc`] and friends.
+#[derive(Debug, Error)]
+pub enum PortAllocError {
+    /// Every port in the `LOW..HIGH` window is reserved or bound, so no range
+    /// of `range_size` could be allocated. Returned instead of spinning
+    /// forever once a full sweep after reclaiming expired entries finds
+    /// nothing free.
+    #[error("no free range of {range_size} ports in {window:?}")]
+    NoFreePorts {
+        range_size: u16,
+        window: std::ops::Range<u16>,
+    },
+}
+
+// The caller gets some time to actually start using the port (`bind`), to
+// prevent other callers from re-using it. This could typically be much
+// shorter, as portalloc will not only respect the allocation, but also try to
+// bind before using a given port range. But for tests that temporarily release
+// ports (e.g. restarts, failure simulations, etc.), there's a chance that this
+// can expire and another test snatches the port, so better to keep it around
+// the time the longest test can take. A [`PortRangeLease`] renews this lease
+// on demand while held and frees the range as soon as it is dropped.
+const ALLOCATION_TIME_SECS: u64 = 120;
+
 type UnixTimestamp = u64;
 
 pub fn now_ts() -> UnixTimestamp {
     fedimint_core::time::duration_since_epoch().as_secs()
 }
 
+// Best-effort system boot time, used only to diversify the per-process starting
+// offset across reboots (where pids get recycled). Returns `0` if it can't be
+// read, which still leaves the pid and per-`DataDir` secret to scatter on.
+fn boot_time() -> u64 {
+    std::fs::read_to_string("/proc/stat")
+        .ok()
+        .and_then(|stat| {
+            stat.lines()
+                .find_map(|line| line.strip_prefix("btime "))
+                .and_then(|btime| btime.trim().parse().ok())
+        })
+        .unwrap_or(0)
+}
+
+// A deterministic per-process offset in `0..(HIGH - LOW)`, following the idea of
+// RFC 6056 algorithm 3: a keyed hash over the process identity
+// (`secret || pid || boot_time`). Starting the search at a process-specific
+// point scatters concurrent allocators across the range instead of having them
+// all scan upward from the same shared `next`, cutting `bind` collisions and
+// contention on the advisory lock. A cheap keyed hash is enough here; the file
+// lock still guarantees non-overlap.
+fn process_offset(secret: &[u8]) -> u16 {
+    use std::hash::{Hash, Hasher};
+
+    let window = u32::from(HIGH - LOW);
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    secret.hash(&mut hasher);
+    std::process::id().hash(&mut hasher);
+    boot_time().hash(&mut hasher);
+    (hasher.finish() % u64::from(window)) as u16
+}
+
 pub fn data_dir() -> anyhow::Result<PathBuf> {
     if let Some(env) = std::env::var_os(FM_PORTALLOC_DATA_DIR_ENV) {
         Ok(PathBuf::from(env))
@@ -52,7 +109,97 @@ pub fn data_dir() -> anyhow::Result<PathBuf> {
         bail!("Could not determine port alloc data dir. Try setting FM_PORTALLOC_DATA_DIR");
     }
 }
+/// Options controlling how [`port_alloc_with`] probes candidate ports.
+///
+/// `port_alloc` only tries `TcpListener::bind(("127.0.0.1", port))`, so a range
+/// it hands out can still collide with a UDP socket or a service that binds a
+/// wildcard address. These options let callers require that every port in a
+/// range binds for each requested protocol on each requested address before the
+/// range is accepted.
+#[derive(Debug, Clone)]
+pub struct PortAllocOptions {
+    tcp: bool,
+    udp: bool,
+    addresses: Vec<IpAddr>,
+}
+
+impl Default for PortAllocOptions {
+    fn default() -> Self {
+        Self {
+            tcp: true,
+            udp: false,
+            addresses: vec![Ipv4Addr::LOCALHOST.into()],
+        }
+    }
+}
+
+impl PortAllocOptions {
+    /// Require (or not) that every port binds over TCP. Enabled by default.
+    pub fn tcp(mut self, enable: bool) -> Self {
+        self.tcp = enable;
+        self
+    }
+
+    /// Require (or not) that every port binds over UDP. Disabled by default.
+    pub fn udp(mut self, enable: bool) -> Self {
+        self.udp = enable;
+        self
+    }
+
+    /// Add an address that every port must bind on (e.g. `0.0.0.0` for services
+    /// that bind the wildcard address).
+    pub fn with_address(mut self, address: IpAddr) -> Self {
+        if !self.addresses.contains(&address) {
+            self.addresses.push(address);
+        }
+        self
+    }
+
+    /// Also require that every port binds on the IPv6 loopback `::1`.
+    pub fn with_ipv6_localhost(self) -> Self {
+        self.with_address(Ipv6Addr::LOCALHOST.into())
+    }
+
+    /// Try to bind `port` for every requested protocol on every requested
+    /// address, returning `false` as soon as any bind fails.
+    fn try_bind(&self, port: u16) -> bool {
+        for &address in &self.addresses {
+            if self.tcp {
+                match TcpListener::bind((address, port)) {
+                    Ok(_) => {}
+                    Err(error) => {
+                        warn!(
+                            ?error,
+                            port, %address, "Could not bind TCP port. Will try a different range"
+                        );
+                        return false;
+                    }
+                }
+            }
+            if self.udp {
+                match UdpSocket::bind((address, port)) {
+                    Ok(_) => {}
+                    Err(error) => {
+                        warn!(
+                            ?error,
+                            port, %address, "Could not bind UDP port. Will try a different range"
+                        );
+                        return false;
+                    }
+                }
+            }
+        }
+        true
+    }
+}
+
 pub fn port_alloc(range_size: u16) -> anyhow::Result<u16> {
+    port_alloc_with(range_size, &PortAllocOptions::default())
+}
+
+/// Like [`port_alloc`], but only accepts a range when every port binds for each
+/// protocol and address in `options`.
+pub fn port_alloc_with(range_size: u16, options: &PortAllocOptions) -> anyhow::Result<u16> {
     trace!(target: LOG_PORT_ALLOC, range_size, "Looking for port");
     if range_size == 0 {
         bail!("Can't allocate range of 0 bytes");
@@ -62,12 +209,35 @@ pub fn port_alloc(range_size: u16) -> anyhow::Result<u16> {
 
     data_dir.with_lock(|data_dir| {
         let mut data = data_dir.load_data(now_ts())?;
-        let mut base_port: u16 = data.next;
+        // Start the scan at a per-process offset from the shared `next` so
+        // concurrent allocators don't all pile onto the same low ports. The
+        // real chosen `next` is still recorded below, keeping ranges
+        // non-overlapping across processes.
+        let window = u32::from(HIGH - LOW);
+        let offset = u32::from(process_offset(data.secret()));
+        // `saturating_sub`: `port_free` never lowers `next` below `LOW`, but a
+        // data file holding a smaller `next` would otherwise make a plain
+        // `u16` subtraction panic (debug) or wrap (release).
+        let mut base_port: u16 =
+            LOW + ((u32::from(data.next.saturating_sub(LOW)) + offset) % window) as u16;
+        // Bound the loop: we allow exactly one `reclaim` of expired entries,
+        // then one full `LOW..HIGH` sweep. If that sweep also wraps past `HIGH`
+        // the window is genuinely exhausted, so fail promptly instead of
+        // spinning forever.
+        let mut reclaimed = false;
         Ok('retry: loop {
             trace!(target: LOG_PORT_ALLOC, base_port, range_size, "Checking a port");
             if HIGH < base_port {
+                if reclaimed {
+                    return Err(PortAllocError::NoFreePorts {
+                        range_size,
+                        window: LOW..HIGH,
+                    }
+                    .into());
+                }
                 data = data.reclaim(now_ts());
                 base_port = LOW;
+                reclaimed = true;
             }
             let range = base_port..base_port + range_size;
             if let Some(next_port) = data.contains(range.clone()) {
@@ -81,28 +251,12 @@ pub fn port_alloc(range_size: u16) -> anyhow::Result<u16> {
             }
 
             for port in range.clone() {
-                match TcpListener::bind(("127.0.0.1", port)) {
-                    Err(error) => {
-                        warn!(
-                            ?error,
-                            port, "Could not use a port. Will try a different range"
-                        );
-                        base_port = port + 1;
-                        continue 'retry;
-                    }
-                    Ok(l) => l,
-                };
+                if !options.try_bind(port) {
+                    base_port = port + 1;
+                    continue 'retry;
+                }
             }
 
-            const ALLOCATION_TIME_SECS: u64 = 120;
-
-            // The caller gets some time actually start using the port (`bind`),
-            // to prevent other callers from re-using it. This could typically be
-            // much shorter, as portalloc will not only respect the allocation,
-            // but also try to bind before using a given port range. But for tests
-            // that temporarily release ports (e.g. restarts, failure simulations, etc.),
-            // there's a chance that this can expire and another tests snatches the test,
-            // so better to keep it around the time a longest test can take.
             data.insert(range, now_ts() + ALLOCATION_TIME_SECS);
 
             data_dir.store_data(&data)?;
@@ -112,3 +266,148 @@ pub fn port_alloc(range_size: u16) -> anyhow::Result<u16> {
         })
     })
 }
+
+/// Release a previously allocated port range before its lease expires.
+///
+/// `port_alloc` only reclaims a range once its `ALLOCATION_TIME_SECS` lease
+/// times out, which keeps the range reserved long after the caller is done
+/// with it. Tests that restart services or simulate failures can call this to
+/// hand the ports back immediately instead of leaking them for two minutes.
+///
+/// The freed range is removed from the shared file and `data.next` is lowered
+/// to `min(next, base_port)`, never below `LOW`, moving the shared scan start
+/// back toward the freed ports. Each allocation still adds its per-process
+/// offset to `next`, so the next scan does not necessarily begin at the freed
+/// range.
+pub fn port_free(base_port: u16, range_size: u16) -> anyhow::Result<()> {
+    trace!(target: LOG_PORT_ALLOC, base_port, range_size, "Freeing port range");
+    if range_size == 0 {
+        bail!("Can't free range of 0 bytes");
+    }
+
+    let mut data_dir = DataDir::new(data_dir()?)?;
+
+    data_dir.with_lock(|data_dir| {
+        let mut data = data_dir.load_data(now_ts())?;
+        data.remove(base_port..base_port + range_size);
+        // Never lower `next` below `LOW`: a stray free of an out-of-window port
+        // must not poison every subsequent allocation's scan start.
+        data.next = data.next.min(base_port.max(LOW));
+        data_dir.store_data(&data)?;
+        debug!(target: LOG_PORT_ALLOC, base_port, range_size, "Freed port range");
+        Ok(())
+    })
+}
+
+/// Allocate a port range like [`port_alloc`], returning an RAII guard that
+/// frees it again on `Drop`.
+///
+/// This avoids the "keep the lease as long as the longest test" tradeoff baked
+/// into [`ALLOCATION_TIME_SECS`]: the guard keeps the range reserved only while
+/// it is actually held, extending the lease on demand via
+/// [`PortRangeLease::renew`], and returns the ports as soon as it is dropped. A
+/// process that crashes still has its range reclaimed by the normal expiry
+/// sweep in [`port_alloc`].
+pub fn port_alloc_lease(range_size: u16) -> anyhow::Result<PortRangeLease> {
+    let base_port = port_alloc(range_size)?;
+    Ok(PortRangeLease {
+        base_port,
+        range_size,
+    })
+}
+
+/// An RAII guard over a port range allocated by [`port_alloc_lease`].
+///
+/// The range is freed via [`port_free`] when the guard is dropped. Call
+/// [`renew`](Self::renew) periodically to extend the lease while the range is
+/// in use for longer than [`ALLOCATION_TIME_SECS`].
+#[derive(Debug)]
+pub struct PortRangeLease {
+    base_port: u16,
+    range_size: u16,
+}
+
+impl PortRangeLease {
+    /// The first port of the leased range.
+    pub fn base_port(&self) -> u16 {
+        self.base_port
+    }
+
+    /// The number of ports in the leased range.
+    pub fn range_size(&self) -> u16 {
+        self.range_size
+    }
+
+    /// Extend the lease by rewriting its `expires` timestamp to
+    /// `now + ALLOCATION_TIME_SECS`, so a long-running holder does not lose its
+    /// ports to the expiry sweep.
+    pub fn renew(&self) -> anyhow::Result<()> {
+        let mut data_dir = DataDir::new(data_dir()?)?;
+
+        data_dir.with_lock(|data_dir| {
+            let mut data = data_dir.load_data(now_ts())?;
+            data.insert(
+                self.base_port..self.base_port + self.range_size,
+                now_ts() + ALLOCATION_TIME_SECS,
+            );
+            data_dir.store_data(&data)?;
+            trace!(
+                target: LOG_PORT_ALLOC,
+                base_port = self.base_port,
+                range_size = self.range_size,
+                "Renewed port range lease"
+            );
+            Ok(())
+        })
+    }
+}
+
+impl Drop for PortRangeLease {
+    fn drop(&mut self) {
+        if let Err(error) = port_free(self.base_port, self.range_size) {
+            warn!(
+                target: LOG_PORT_ALLOC,
+                ?error,
+                base_port = self.base_port,
+                range_size = self.range_size,
+                "Failed to free port range on lease drop; it will expire normally"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_exhaustion_instead_of_hanging() {
+        let dir = std::env::temp_dir().join(format!("fm-portalloc-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::env::set_var(FM_PORTALLOC_DATA_DIR_ENV, &dir);
+
+        // Reserve the whole window with a lease far in the future so nothing
+        // can be reclaimed.
+        let mut data_dir = DataDir::new(data_dir().unwrap()).unwrap();
+        data_dir
+            .with_lock(|data_dir| {
+                let mut data = data_dir.load_data(now_ts())?;
+                // Reserve the full window *inclusive* of `HIGH`: the scan guard
+                // is `HIGH < base_port`, so port `HIGH` itself is allocatable
+                // and must be reserved too, or `port_alloc(1)` could bind it and
+                // return `Ok` instead of exercising the exhaustion path.
+                data.insert(LOW..HIGH + 1, now_ts() + 1_000_000);
+                data_dir.store_data(&data)?;
+                Ok(())
+            })
+            .unwrap();
+
+        let err = port_alloc(1).expect_err("the window is fully reserved");
+        assert!(matches!(
+            err.downcast_ref::<PortAllocError>(),
+            Some(PortAllocError::NoFreePorts { range_size: 1, .. })
+        ));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}